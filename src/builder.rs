@@ -0,0 +1,263 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use fst::{Error, MapBuilder};
+
+use config::Config;
+use ngrams;
+use shard::QueryType;
+use sorter::{ExternalSorter, NormRecord, NormSorter, Record};
+use stopwords;
+use util;
+
+/// Builds the `map.N` FST and `shard.N` posting-bucket files for shards
+/// `first_shard..last_shard`, reading `{input_dir}/shard.N.input` lines of the
+/// form `qid\tquery` and writing the result to `output_dir`.
+pub fn index(
+    input_dir: &str,
+    first_shard: usize,
+    last_shard: usize,
+    output_dir: &str,
+) -> Result<(), Error> {
+    let config = Config::init(output_dir.to_string());
+
+    let stopword_set = stopwords::load(&config.stopwords_path)
+        .unwrap_or_else(|_| panic!("Failed to load stop-words!"));
+
+    let terms_relevance = fst::Map::from_path(&config.terms_relevance_path)
+        .unwrap_or_else(|_| panic!("Failed to load terms rel. map!"));
+
+    for shard_id in first_shard..last_shard {
+        index_shard(
+            input_dir,
+            shard_id,
+            output_dir,
+            &config,
+            &stopword_set,
+            &terms_relevance,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn index_shard(
+    input_dir: &str,
+    shard_id: usize,
+    output_dir: &str,
+    config: &Config,
+    stopword_set: &::std::collections::HashSet<String>,
+    terms_relevance: &fst::Map,
+) -> Result<(), Error> {
+    let input_path = format!("{}/shard.{}.input", input_dir, shard_id);
+    let input = File::open(&input_path)?;
+    let reader = BufReader::new(input);
+
+    // (ngram, posting) records are buffered up to config.index_memory_budget_bytes
+    // and spilled to sorted runs under a per-shard spill dir beyond that, so
+    // peak memory stays bounded regardless of how large the shard's input is.
+    let spill_dir = format!("{}/{}", config.spill_dir, shard_id);
+    let mut sorter = ExternalSorter::new(&spill_dir, config.index_memory_budget_bytes)?;
+
+    // Per-document sum of squared term weights, reduced to an L2 norm below
+    // once all of a document's ngrams have been seen. Keyed by the full qid
+    // rather than pqid: a shard can hold two distinct documents that share a
+    // pqid (same qid / nr_shards, different qid % nr_shards), and summing
+    // their contributions together under one key would corrupt both norms.
+    // Routed through the same kind of bounded external sort as postings
+    // (sorted by qid instead of ngram) rather than a HashMap, since a shard
+    // can hold as many distinct qids as it has documents.
+    let norm_spill_dir = format!("{}/{}-norms", config.spill_dir, shard_id);
+    let mut norm_sorter = NormSorter::new(&norm_spill_dir, config.index_memory_budget_bytes)?;
+
+    for line in reader.lines() {
+        let line = line?;
+        let mut parts = line.splitn(2, '\t');
+        let qid: u64 = match parts.next().and_then(|s| s.parse().ok()) {
+            Some(qid) => qid,
+            None => continue,
+        };
+        let query = match parts.next() {
+            Some(q) => q,
+            None => continue,
+        };
+
+        let pqid = (qid / config.nr_shards as u64) as u32;
+        let reminder = (qid % config.nr_shards as u64) as u8;
+
+        let ngrams = ngrams::parse(query, stopword_set, terms_relevance, QueryType::Q);
+        // posting's `f` is this document's ngram count, capped the same way
+        // `tr` is; `Legacy` scoring uses it as a document-length boost, so
+        // it needs to reflect a real count rather than a constant.
+        let ngram_count = ngrams.len() as u32;
+        let f = ngram_count.min(255) as u8;
+
+        let mut sq_weight_sum = 0.0f32;
+        for (ngram, tr) in ngrams {
+            sq_weight_sum += tr * tr;
+            sorter.push(Record {
+                ngram: ngram,
+                pqid: pqid,
+                reminder: reminder,
+                tr: (tr * 100.0).min(255.0) as u8,
+                f: f,
+            })?;
+        }
+        norm_sorter.push(NormRecord {
+            qid: qid,
+            sq_weight: sq_weight_sum,
+            ngram_count: ngram_count,
+        })?;
+    }
+
+    let bucket_path = format!("{}/shard.{}", output_dir, shard_id);
+    let map_path = format!("{}/map.{}", output_dir, shard_id);
+
+    let mut bucket_writer = BufWriter::new(File::create(&bucket_path)?);
+    let mut map_builder = MapBuilder::new(File::create(&map_path)?)?;
+
+    let mut addr: u64 = 0;
+    let mut current_ngram: Option<String> = None;
+    let mut current_len: u64 = 0;
+
+    // The sorter streams records in non-decreasing ngram order via a k-way
+    // merge of its spilled runs, so a single pass groups each ngram's
+    // postings together and hands the FST builder strictly increasing keys
+    // without ever holding the full shard in memory.
+    for record in sorter.finish()? {
+        if current_ngram.as_ref() != Some(&record.ngram) {
+            if let Some(ngram) = current_ngram.take() {
+                map_builder.insert(&ngram, util::elegant_pair(addr, current_len))?;
+                addr += current_len;
+            }
+            current_ngram = Some(record.ngram.clone());
+            current_len = 0;
+        }
+
+        // `lib::read_bucket` allocates a fixed `config.bucket_size * id_size`
+        // buffer per read, sized for the largest bucket the reader is meant
+        // to see. Any ngram whose posting list runs longer than that would
+        // make it index past the buffer it allocated, so postings beyond
+        // `bucket_size` are dropped here rather than written.
+        if current_len >= config.bucket_size as u64 {
+            continue;
+        }
+
+        bucket_writer.write_u32::<LittleEndian>(record.pqid)?;
+        bucket_writer.write_u8(record.reminder)?;
+        bucket_writer.write_u8(record.tr)?;
+        bucket_writer.write_u8(record.f)?;
+        current_len += 1;
+    }
+    if let Some(ngram) = current_ngram.take() {
+        map_builder.insert(&ngram, util::elegant_pair(addr, current_len))?;
+    }
+
+    map_builder.finish()?;
+    bucket_writer.flush()?;
+
+    ExternalSorter::cleanup(&spill_dir);
+
+    write_norms(output_dir, shard_id, norm_sorter)?;
+    NormSorter::cleanup(&norm_spill_dir);
+
+    Ok(())
+}
+
+/// Writes the `norm.N` (`qid: u64, l2_norm: f32`) and `doclen.N`
+/// (`qid: u64, ngram_count: u32`) side tables, plus the shard's average
+/// document length (`avgdl.N`, a single `f32`) used by `Bm25`'s length
+/// normalization. `Cosine` divides by a document's entry in `norm.N`
+/// directly and has no use for `avgdl.N`. Keyed by the full `qid` rather
+/// than `pqid`, since a shard can hold two documents that share a `pqid`
+/// (see the comment on `NormRecord`) and those must stay distinct entries
+/// here. Drains `norm_sorter`'s qid-ordered stream in one pass, summing
+/// adjacent same-qid contributions as it goes, so this never holds more
+/// than one document's running totals in memory regardless of the shard's
+/// document count.
+fn write_norms(output_dir: &str, shard_id: usize, norm_sorter: NormSorter) -> Result<(), Error> {
+    let norm_path = format!("{}/norm.{}", output_dir, shard_id);
+    let mut norm_writer = BufWriter::new(File::create(&norm_path)?);
+
+    let doclen_path = format!("{}/doclen.{}", output_dir, shard_id);
+    let mut doclen_writer = BufWriter::new(File::create(&doclen_path)?);
+
+    let mut total_ngram_count = 0.0f64;
+    let mut nr_docs: u64 = 0;
+
+    let mut current_qid: Option<u64> = None;
+    let mut current_sq_weight_sum = 0.0f32;
+    let mut current_ngram_count = 0u32;
+
+    let mut flush = |qid: u64,
+                     sq_weight_sum: f32,
+                     ngram_count: u32,
+                     norm_writer: &mut BufWriter<File>,
+                     doclen_writer: &mut BufWriter<File>,
+                     total_ngram_count: &mut f64,
+                     nr_docs: &mut u64|
+     -> Result<(), Error> {
+        norm_writer.write_u64::<LittleEndian>(qid)?;
+        norm_writer.write_f32::<LittleEndian>(sq_weight_sum.sqrt())?;
+
+        doclen_writer.write_u64::<LittleEndian>(qid)?;
+        doclen_writer.write_u32::<LittleEndian>(ngram_count)?;
+
+        *total_ngram_count += ngram_count as f64;
+        *nr_docs += 1;
+        Ok(())
+    };
+
+    for record in norm_sorter.finish()? {
+        match current_qid {
+            Some(qid) if qid == record.qid => {
+                current_sq_weight_sum += record.sq_weight;
+                current_ngram_count += record.ngram_count;
+            }
+            Some(qid) => {
+                flush(
+                    qid,
+                    current_sq_weight_sum,
+                    current_ngram_count,
+                    &mut norm_writer,
+                    &mut doclen_writer,
+                    &mut total_ngram_count,
+                    &mut nr_docs,
+                )?;
+                current_qid = Some(record.qid);
+                current_sq_weight_sum = record.sq_weight;
+                current_ngram_count = record.ngram_count;
+            }
+            None => {
+                current_qid = Some(record.qid);
+                current_sq_weight_sum = record.sq_weight;
+                current_ngram_count = record.ngram_count;
+            }
+        }
+    }
+    if let Some(qid) = current_qid {
+        flush(
+            qid,
+            current_sq_weight_sum,
+            current_ngram_count,
+            &mut norm_writer,
+            &mut doclen_writer,
+            &mut total_ngram_count,
+            &mut nr_docs,
+        )?;
+    }
+    norm_writer.flush()?;
+    doclen_writer.flush()?;
+
+    let avgdl = if nr_docs == 0 {
+        1.0
+    } else {
+        (total_ngram_count / nr_docs as f64) as f32
+    };
+
+    let avgdl_path = format!("{}/avgdl.{}", output_dir, shard_id);
+    File::create(&avgdl_path)?.write_f32::<LittleEndian>(avgdl)?;
+
+    Ok(())
+}
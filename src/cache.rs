@@ -0,0 +1,86 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use lru::LruCache;
+
+// Spreads the cache across several independently-locked shards so hot
+// ngrams from concurrent queries (see the parallel shard fan-out in
+// `Qpick::get_ids`) don't all serialize on one mutex.
+const NR_CACHE_SHARDS: usize = 16;
+
+type Bucket = Vec<(u32, u8, u8, u8)>;
+
+/// Bounded LRU cache of decoded posting buckets, keyed by `(shard_id, addr)`
+/// so `get_query_ids` can skip re-seeking and re-decoding a shard file for
+/// ngrams it has already served recently.
+pub struct BucketCache {
+    shards: Vec<Mutex<LruCache<(usize, u64), Bucket>>>,
+}
+
+impl BucketCache {
+    /// `capacity` is the total number of decoded buckets to keep cached,
+    /// split evenly (rounding down) across the internal lock shards. Each
+    /// lock shard keeps at least one bucket, so a `capacity` below the
+    /// number of lock shards still keeps up to that many buckets.
+    pub fn new(capacity: usize) -> BucketCache {
+        let per_shard = ::std::cmp::max(1, capacity / NR_CACHE_SHARDS);
+        let shards = (0..NR_CACHE_SHARDS)
+            .map(|_| Mutex::new(LruCache::new(per_shard)))
+            .collect();
+
+        BucketCache { shards: shards }
+    }
+
+    fn lock_for(&self, key: &(usize, u64)) -> &Mutex<LruCache<(usize, u64), Bucket>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let idx = (hasher.finish() as usize) % self.shards.len();
+        &self.shards[idx]
+    }
+
+    pub fn get(&self, shard_id: usize, addr: u64) -> Option<Bucket> {
+        let key = (shard_id, addr);
+        self.lock_for(&key).lock().unwrap().get(&key).cloned()
+    }
+
+    pub fn put(&self, shard_id: usize, addr: u64, bucket: Bucket) {
+        let key = (shard_id, addr);
+        self.lock_for(&key).lock().unwrap().put(key, bucket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_returns_what_was_put() {
+        let cache = BucketCache::new(16);
+        let bucket: Bucket = vec![(1, 2, 3, 4)];
+
+        cache.put(0, 42, bucket.clone());
+
+        assert_eq!(cache.get(0, 42), Some(bucket));
+        assert_eq!(cache.get(0, 999), None);
+        // a different shard_id is a different key even with the same addr
+        assert_eq!(cache.get(1, 42), None);
+    }
+
+    #[test]
+    fn test_capacity_bounds_total_entries() {
+        let capacity = 16;
+        let cache = BucketCache::new(capacity);
+
+        for addr in 0..1000u64 {
+            cache.put(0, addr, vec![(addr as u32, 0, 0, 0)]);
+        }
+
+        // however the 1000 puts distributed across the 16 lock shards, the
+        // configured capacity must still bound how many survive, i.e. total
+        // cached entries don't scale with how many buckets were decoded.
+        let hits = (0..1000u64).filter(|&addr| cache.get(0, addr).is_some()).count();
+        assert!(hits > 0);
+        assert!(hits <= capacity);
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io::Read;
+
+use serde_json;
+
+/// Selects how `get_query_ids` turns matched postings into a score.
+///
+/// `Legacy` reproduces the original ad-hoc IDF weighting (divided by a
+/// single query-wide normalization factor) so indexes built before the
+/// `norm.N` side tables existed keep scoring exactly as before. `Cosine`
+/// and `Bm25` both need the indexing-time document norms written by
+/// `builder::index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ScoringMode {
+    Legacy,
+    Cosine,
+    Bm25,
+}
+
+impl Default for ScoringMode {
+    fn default() -> ScoringMode {
+        ScoringMode::Legacy
+    }
+}
+
+fn default_bm25_k1() -> f32 {
+    1.2
+}
+
+fn default_bm25_b() -> f32 {
+    0.75
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+fn default_index_memory_budget() -> usize {
+    // 64MiB of buffered (ngram, posting) records before builder::index spills
+    // a sorted run to disk.
+    64 * 1024 * 1024
+}
+
+fn default_spill_dir() -> String {
+    "/tmp/qpick-spill".to_string()
+}
+
+fn default_bucket_cache_capacity() -> usize {
+    // decoded posting buckets, not bytes; spread across cache::BucketCache's
+    // internal lock shards.
+    100_000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub nr_shards: usize,
+    pub id_size: usize,
+    pub bucket_size: usize,
+    pub shard_size: usize,
+    pub stopwords_path: String,
+    pub terms_relevance_path: String,
+
+    /// Max Levenshtein edit distance of the extra keys a fuzzy lookup pulls in
+    /// from a shard's FST map alongside an ngram's exact match (if any); the
+    /// fuzzy keys' postings are discounted by their edit distance. `0` (the
+    /// default) keeps lookups exact-only;
+    /// automaton size grows steeply with distance, so this is clamped to 2
+    /// wherever it's read.
+    #[serde(default)]
+    pub fuzzy_max_edit_distance: usize,
+
+    /// How `get_query_ids` scores a query against a document. Defaults to
+    /// `Legacy` so indexes built before document norms were tracked keep
+    /// working unchanged.
+    #[serde(default)]
+    pub scoring_mode: ScoringMode,
+
+    /// BM25 term-frequency saturation parameter. Only used when
+    /// `scoring_mode` is `Bm25`.
+    #[serde(default = "default_bm25_k1")]
+    pub bm25_k1: f32,
+
+    /// BM25 document-length normalization parameter. Only used when
+    /// `scoring_mode` is `Bm25`.
+    #[serde(default = "default_bm25_b")]
+    pub bm25_b: f32,
+
+    /// Size of the thread pool used to fan a query out across shards and to
+    /// drive `AsyncClient` lookups in the background. Same concept as
+    /// `shard::shard`'s `concurrency` argument, just applied at query time.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+
+    /// Byte budget for `builder::index`'s in-memory (ngram, posting) buffer
+    /// before it sorts and spills a run to `spill_dir`. Bounds peak indexing
+    /// memory regardless of corpus size.
+    #[serde(default = "default_index_memory_budget")]
+    pub index_memory_budget_bytes: usize,
+
+    /// Directory `builder::index` spills sorted runs to while indexing.
+    #[serde(default = "default_spill_dir")]
+    pub spill_dir: String,
+
+    /// Number of decoded posting buckets `cache::BucketCache` keeps around
+    /// across all shards, so repeat lookups of hot ngrams skip re-seeking
+    /// and re-decoding their shard file.
+    #[serde(default = "default_bucket_cache_capacity")]
+    pub bucket_cache_capacity: usize,
+}
+
+impl Config {
+    pub fn init(path: String) -> Config {
+        let config_path = format!("{}/config.json", path);
+
+        let mut f = File::open(&config_path)
+            .unwrap_or_else(|_| panic!("Failed to open config: {}", &config_path));
+
+        let mut data = String::new();
+        f.read_to_string(&mut data)
+            .unwrap_or_else(|_| panic!("Failed to read config: {}", &config_path));
+
+        serde_json::from_str(&data).unwrap_or_else(|_| panic!("Failed to parse config: {}", &config_path))
+    }
+}
@@ -1,6 +1,11 @@
 extern crate byteorder;
 extern crate fst;
+extern crate fst_levenshtein;
+extern crate futures;
+extern crate futures_cpupool;
 extern crate libc;
+extern crate lru;
+extern crate rayon;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
@@ -17,19 +22,28 @@ use std::collections::{HashMap, HashSet};
 use byteorder::{ByteOrder, LittleEndian};
 use fst::Map;
 use fst::raw::{Fst, MmapReadOnly};
+use fst::{IntoStreamer, Streamer};
+use fst_levenshtein::Levenshtein;
+use futures::Future;
+use futures_cpupool::CpuPool;
+use rayon::prelude::*;
+use rayon::ThreadPool;
 use std::io::SeekFrom;
 
 use fst::Error;
 
 #[macro_use]
 pub mod util;
+pub mod cache;
 pub mod config;
 pub mod ngrams;
 pub mod merge;
 pub mod shard;
+pub mod sorter;
 pub mod builder;
 pub mod stopwords;
 
+use config::ScoringMode;
 use shard::QueryType;
 
 macro_rules! make_static_var_and_getter {
@@ -79,6 +93,31 @@ fn read_bucket(mut file: &File, addr: u64, len: u64) -> Vec<(u32, u8, u8, u8)> {
     vector
 }
 
+// Consults `cache` before falling back to `read_bucket`, so hot ngrams pay
+// the seek-and-decode cost once instead of on every query that touches them.
+// `shard_id` plus the FST-supplied `addr` uniquely identify a bucket.
+fn read_bucket_cached(
+    cache: &cache::BucketCache,
+    shard_id: usize,
+    file: &File,
+    addr: u64,
+    len: u64,
+) -> Vec<(u32, u8, u8, u8)> {
+    if let Some(bucket) = cache.get(shard_id, addr) {
+        return bucket;
+    }
+
+    let id_size = *get_id_size();
+    let bucket = read_bucket(file, addr * id_size as u64, len);
+    cache.put(shard_id, addr, bucket.clone());
+    bucket
+}
+
+// Hard ceiling on how many of the keys a single fuzzy expansion pulls out of
+// an FST map are kept (closest first), regardless of how permissive the edit
+// distance is.
+const MAX_FUZZY_MATCHES: usize = 32;
+
 // reading part
 #[inline]
 fn get_addr_and_len(ngram: &str, map: &fst::Map) -> Option<(u64, u64)> {
@@ -88,6 +127,61 @@ fn get_addr_and_len(ngram: &str, map: &fst::Map) -> Option<(u64, u64)> {
     }
 }
 
+// Looks up `ngram` plus, when `max_edit_distance > 0`, every key in `map`
+// within that many edits of it via a Levenshtein automaton over the FST.
+// Each match carries its edit distance from `ngram` so the caller can
+// discount fuzzy hits relative to the exact one. Exact-only (the default)
+// when `max_edit_distance` is 0. There's no additional length guard here:
+// every `ngram` this is called with came out of `ngrams::parse`, which
+// already skips whole query terms shorter than its fixed ngram size, so by
+// the time a string reaches this function it's always exactly that length.
+fn get_fuzzy_addrs_and_lens(
+    ngram: &str,
+    map: &fst::Map,
+    max_edit_distance: usize,
+) -> Vec<(u64, u64, usize)> {
+    if max_edit_distance == 0 {
+        return match get_addr_and_len(ngram, map) {
+            Some((addr, len)) => vec![(addr, len, 0)],
+            None => vec![],
+        };
+    }
+
+    // automaton size grows steeply with distance, so this is capped at 2
+    // even if a larger value leaked in through config.
+    let max_edit_distance = std::cmp::min(max_edit_distance, 2) as u32;
+
+    let lev = match Levenshtein::new(ngram, max_edit_distance) {
+        Ok(lev) => lev,
+        Err(_) => return vec![],
+    };
+
+    let mut stream = map.search(lev).into_stream();
+    let mut matches = Vec::new();
+
+    // `map.search` streams keys in lexicographic order, not by edit
+    // distance, so truncating here (as the cap eventually does below) could
+    // drop the distance-0 exact key in favor of lexicographically-earlier
+    // fuzzy ones. Collect every match the automaton yields first and only
+    // then rank by edit distance, so exact hits are never discarded in
+    // favor of a fuzzy one.
+    while let Some((key, val)) = stream.next() {
+        let key = match std::str::from_utf8(key) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+
+        let (addr, len) = util::elegant_pair_inv(val);
+        let edit_distance = util::levenshtein_distance(ngram, key);
+        matches.push((addr, len, edit_distance));
+    }
+
+    matches.sort_by_key(|&(_, _, edit_distance)| edit_distance);
+    matches.truncate(MAX_FUZZY_MATCHES);
+
+    matches
+}
+
 // Advise the OS on the random access pattern of data.
 // Taken from https://docs.rs/crate/madvise/0.1.0
 #[cfg(unix)]
@@ -134,44 +228,113 @@ impl PartialEq for Sid {
     }
 }
 
+/// Scoring knobs threaded down from `config::Config` into `get_query_ids`,
+/// kept together so legacy callers only have to pass one extra argument.
+struct ScoringOptions<'a> {
+    mode: ScoringMode,
+    bm25_k1: f32,
+    bm25_b: f32,
+    // indexing-time L2 norm of each document's weighted ngram vector, keyed
+    // by the full qid; `Cosine` divides by this directly.
+    norms: &'a HashMap<u64, f32>,
+    // indexing-time ngram count of each document, keyed by the full qid;
+    // `Bm25`'s length normalization term. Distinct from `norms` since a
+    // weighted-vector magnitude and a token count aren't the same notion of
+    // "length".
+    doc_lens: &'a HashMap<u64, u32>,
+    avgdl: f32,
+    // ||q||, the L2 norm of the *complete* query's ngram-weight vector.
+    // Computed once over every ngram the query expands to (see
+    // `Qpick::get_ids`), not just the subset routed to a given shard, so
+    // `Cosine` scores stay comparable across shards.
+    qnorm: f32,
+}
+
 fn get_query_ids(
     ngrams: &HashMap<String, f32>,
     map: &fst::Map,
     ifd: &File,
+    shard_id: usize,
+    bucket_cache: &cache::BucketCache,
     count: usize,
+    fuzzy_max_edit_distance: usize,
+    scoring: &ScoringOptions,
 ) -> Result<ShardIds, Error> {
     let mut _ids = HashMap::new();
     let mut _norm: f32 = 0.0;
-    let id_size = *get_id_size();
     let n = *get_shard_size() as f32;
     for (ngram, ntr) in ngrams {
         // IDF score for the ngram
         let mut _idf: f32 = 0.0;
-        match get_addr_and_len(ngram, &map) {
-            // returns physical memory address and length of the vector (not a number of bytes)
-            Some((addr, len)) => {
-                for pqid_rem_tr_f in read_bucket(&ifd, addr * id_size as u64, len).iter() {
+        // returns physical memory address and length of the vector (not a
+        // number of bytes) for every match within `fuzzy_max_edit_distance`
+        // edits of `ngram` (just the exact match when fuzzy is disabled).
+        let matches = get_fuzzy_addrs_and_lens(ngram, &map, fuzzy_max_edit_distance);
+
+        if matches.is_empty() {
+            // IDF for non existing ngram, occurs for the 1st time
+            _idf = n.log(2.0);
+        } else {
+            for &(addr, len, edit_distance) in matches.iter() {
+                // exact hits still dominate: fuzzy matches are discounted by
+                // how far they are from the original ngram.
+                let penalty = 1.0 / (1.0 + edit_distance as f32);
+                let idf = (n / len as f32).log(2.0);
+
+                for pqid_rem_tr_f in
+                    read_bucket_cached(bucket_cache, shard_id, &ifd, addr, len).iter()
+                {
                     let pqid = pqid_rem_tr_f.0;
                     let reminder = pqid_rem_tr_f.1;
                     let qid = util::pqid2qid(pqid as u64, reminder, *get_nr_shards());
-                    // TODO cosine similarity, normalize ngrams relevance at indexing time
                     let f = pqid_rem_tr_f.3;
                     let tr = pqid_rem_tr_f.2;
-                    let weight = util::min((tr as f32) / 100.0, *ntr) * (1.0 + f as f32 / 1000.0);
-                    *_ids.entry(qid).or_insert(0.0) += weight * (n / len as f32).log(2.0);
+                    let doc_weight = tr as f32 / 100.0;
+
+                    let contribution = match scoring.mode {
+                        ScoringMode::Legacy => {
+                            let weight =
+                                util::min(doc_weight, *ntr) * (1.0 + f as f32 / 1000.0);
+                            weight * penalty * idf
+                        }
+                        ScoringMode::Cosine => {
+                            // numerator of score(qid) = (Σ q_i·d_i) / (||q||·||d_qid||);
+                            // the division by the two norms happens once, below.
+                            ntr * doc_weight * penalty
+                        }
+                        ScoringMode::Bm25 => {
+                            let doc_len = scoring
+                                .doc_lens
+                                .get(&qid)
+                                .map(|&len| len as f32)
+                                .unwrap_or(scoring.avgdl);
+                            let k1 = scoring.bm25_k1;
+                            let b = scoring.bm25_b;
+                            let denom =
+                                doc_weight + k1 * (1.0 - b + b * (doc_len / scoring.avgdl));
+                            idf * (doc_weight * (k1 + 1.0)) / denom * penalty
+                        }
+                    };
+
+                    *_ids.entry(qid).or_insert(0.0) += contribution;
                 }
-                // IDF for existing ngram
-                _idf = (n / len as f32).log(2.0);
-            }
-            None => {
-                // IDF for non existing ngram, occurs for the 1st time
-                _idf = n.log(2.0);
             }
+
+            // IDF for existing ngram, taken from the closest match (`matches`
+            // is sorted by edit distance)
+            _idf = (n / matches[0].1 as f32).log(2.0);
         }
-        // compute the normalization score
+        // compute the normalization score (legacy mode only)
         _norm += ntr * _idf;
     }
 
+    if scoring.mode == ScoringMode::Cosine {
+        for (qid, sc) in _ids.iter_mut() {
+            let doc_norm = *scoring.norms.get(qid).unwrap_or(&1.0);
+            *sc /= scoring.qnorm.max(1e-6) * doc_norm.max(1e-6);
+        }
+    }
+
     let mut v: Vec<Sid> = _ids.iter()
         .map(|(id, sc)| Sid { id: *id, sc: *sc })
         .collect::<Vec<_>>();
@@ -191,11 +354,86 @@ pub struct Qpick {
     terms_relevance: fst::Map,
     shards: Arc<Vec<Shard>>,
     shard_range: Range<u32>,
+    // fans a query's per-shard scoring out across `config.concurrency`
+    // threads; shards are fully independent and each does its own mmap reads.
+    query_pool: ThreadPool,
+    // drives `AsyncClient` lookups on a background pool so embedding a
+    // `Qpick` in a server doesn't block its executor thread.
+    async_pool: CpuPool,
+    // decoded posting buckets for hot ngrams, shared across shards and
+    // concurrent queries; see `read_bucket_cached`.
+    bucket_cache: cache::BucketCache,
 }
 
 pub struct Shard {
     map: fst::Map,
     shard: File,
+    // indexing-time L2 norm of each document's weighted ngram vector, keyed
+    // by the full qid (not pqid: a shard can hold two documents sharing a
+    // pqid, see the comment on `sorter::NormRecord`); `Cosine` divides by
+    // this directly.
+    norms: HashMap<u64, f32>,
+    // indexing-time ngram count of each document, keyed by the full qid;
+    // `Bm25`'s length normalization term.
+    doc_lens: HashMap<u64, u32>,
+    // shard-wide average of `doc_lens`, used as the `Bm25` length
+    // normalizer's `avgdl` and as the fallback for documents missing from
+    // `doc_lens`.
+    avgdl: f32,
+}
+
+/// Reads the `(qid: u64, l2_norm: f32)` records written by
+/// `builder::write_norms`. Missing files (indexes built before norms existed)
+/// yield an empty table, in which case `Cosine` uses a document norm of 1.0
+/// for every document; the scoring mode itself is set only by the config.
+fn load_norms(path: &str) -> HashMap<u64, f32> {
+    let mut norms = HashMap::new();
+
+    let mut buf = Vec::new();
+    if File::open(path).and_then(|mut f| f.read_to_end(&mut buf)).is_err() {
+        return norms;
+    }
+
+    let mut i = 0;
+    while i + 12 <= buf.len() {
+        let qid = LittleEndian::read_u64(&buf[i..i + 8]);
+        let norm = LittleEndian::read_f32(&buf[i + 8..i + 12]);
+        norms.insert(qid, norm);
+        i += 12;
+    }
+
+    norms
+}
+
+/// Reads the `(qid: u64, ngram_count: u32)` records written by
+/// `builder::write_norms`. Missing files (indexes built before doc lengths
+/// were tracked) yield an empty table, so `Bm25` falls back to `avgdl` for
+/// every document.
+fn load_doc_lens(path: &str) -> HashMap<u64, u32> {
+    let mut doc_lens = HashMap::new();
+
+    let mut buf = Vec::new();
+    if File::open(path).and_then(|mut f| f.read_to_end(&mut buf)).is_err() {
+        return doc_lens;
+    }
+
+    let mut i = 0;
+    while i + 12 <= buf.len() {
+        let qid = LittleEndian::read_u64(&buf[i..i + 8]);
+        let len = LittleEndian::read_u32(&buf[i + 8..i + 12]);
+        doc_lens.insert(qid, len);
+        i += 12;
+    }
+
+    doc_lens
+}
+
+fn load_avgdl(path: &str) -> f32 {
+    let mut buf = [0u8; 4];
+    match File::open(path).and_then(|mut f| f.read_exact(&mut buf)) {
+        Ok(()) => LittleEndian::read_f32(&buf),
+        Err(_) => 1.0,
+    }
 }
 
 #[derive(Debug)]
@@ -258,12 +496,27 @@ impl Qpick {
                 .read(true)
                 .open(format!("{}/shard.{}", path, i))
                 .unwrap();
+
+            let norms = load_norms(&format!("{}/norm.{}", path, i));
+            let doc_lens = load_doc_lens(&format!("{}/doclen.{}", path, i));
+            let avgdl = load_avgdl(&format!("{}/avgdl.{}", path, i));
+
             shards.push(Shard {
                 shard: shard,
                 map: map,
+                norms: norms,
+                doc_lens: doc_lens,
+                avgdl: avgdl,
             });
         }
 
+        let query_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(c.concurrency)
+            .build()
+            .expect("Failed to build query thread pool");
+        let async_pool = CpuPool::new(c.concurrency);
+        let bucket_cache = cache::BucketCache::new(c.bucket_cache_capacity);
+
         Qpick {
             config: c,
             path: path,
@@ -271,6 +524,9 @@ impl Qpick {
             terms_relevance: terms_relevance,
             shards: Arc::new(shards),
             shard_range: shard_range,
+            query_pool: query_pool,
+            async_pool: async_pool,
+            bucket_cache: bucket_cache,
         }
     }
 
@@ -286,12 +542,27 @@ impl Qpick {
         &self,
         ngrams: &HashMap<String, f32>,
         count: Option<usize>,
+        fuzzy: bool,
     ) -> Result<Vec<Sid>, Error> {
+        let fuzzy_max_edit_distance = if fuzzy {
+            self.config.fuzzy_max_edit_distance
+        } else {
+            0
+        };
+
         let shard_count = match count {
             Some(1...50) => 100,
             _ => count.unwrap(),
         };
 
+        // L2 norm of the *complete* query's ngram-weight vector, used by
+        // `Cosine` scoring. Computed once here, over every ngram regardless
+        // of which shard it's routed to, rather than per shard, since a
+        // document's postings live in a single shard and so the same ||q||
+        // must be used to normalize every shard's partial scores for them to
+        // be comparable.
+        let qnorm: f32 = ngrams.values().map(|ntr| ntr * ntr).sum::<f32>().sqrt();
+
         let ref mut shards_ngrams: HashMap<usize, HashMap<String, f32>> = HashMap::new();
 
         for (ngram, sc) in ngrams {
@@ -307,17 +578,36 @@ impl Qpick {
             sh_ngrams.insert(ngram.to_string(), *sc);
         }
 
-        let shard_ids: Vec<ShardIds> = shards_ngrams
-            .iter()
-            .map(|sh_ng| {
-                get_query_ids(
-                    &sh_ng.1,
-                    &self.shards[*sh_ng.0].map,
-                    &self.shards[*sh_ng.0].shard,
-                    shard_count,
-                ).unwrap()
-            })
-            .collect();
+        // Shards are fully independent and each does its own mmap reads, so
+        // score them concurrently on the query pool instead of one at a time.
+        let shard_ids: Vec<ShardIds> = self.query_pool.install(|| {
+            shards_ngrams
+                .par_iter()
+                .map(|sh_ng| {
+                    let shard = &self.shards[*sh_ng.0];
+                    let scoring = ScoringOptions {
+                        mode: self.config.scoring_mode,
+                        bm25_k1: self.config.bm25_k1,
+                        bm25_b: self.config.bm25_b,
+                        norms: &shard.norms,
+                        doc_lens: &shard.doc_lens,
+                        avgdl: shard.avgdl,
+                        qnorm: qnorm,
+                    };
+
+                    get_query_ids(
+                        &sh_ng.1,
+                        &shard.map,
+                        &shard.shard,
+                        *sh_ng.0,
+                        &self.bucket_cache,
+                        shard_count,
+                        fuzzy_max_edit_distance,
+                        &scoring,
+                    ).unwrap()
+                })
+                .collect()
+        });
 
         let mut hdata: HashMap<u64, f32> = HashMap::new();
         let mut norm: f32 = 0.0;
@@ -328,12 +618,17 @@ impl Qpick {
             norm += sh_id.norm;
         }
 
+        // Cosine/BM25 scores are already normalized per shard inside
+        // `get_query_ids`; only the legacy mode still needs the global
+        // query-wide normalization factor applied here.
+        let legacy = self.config.scoring_mode == ScoringMode::Legacy;
+
         let mut vdata: Vec<Sid> = hdata
             .iter()
             .map(|(id, sc)| {
                 Sid {
                     id: *id,
-                    sc: *sc / norm,
+                    sc: if legacy { *sc / norm } else { *sc },
                 }
             })
             .collect();
@@ -372,7 +667,15 @@ impl Qpick {
         QpickResults::new(self.nget(qvec, count).into_iter())
     }
 
+    /// Looks up `query` using exact-only ngram matches. See `get_fuzzy` to
+    /// additionally expand every ngram to the keys within
+    /// `config.fuzzy_max_edit_distance` edits of it, whether or not it has an
+    /// exact match.
     pub fn get(&self, query: &str, count: u32) -> Vec<Sid> {
+        self.get_fuzzy(query, count, false)
+    }
+
+    pub fn get_fuzzy(&self, query: &str, count: u32, fuzzy: bool) -> Vec<Sid> {
         if query == "" || count == 0 {
             return vec![];
         }
@@ -380,13 +683,21 @@ impl Qpick {
         let ref ngrams: HashMap<String, f32> =
             ngrams::parse(&query, &self.stopwords, &self.terms_relevance, QueryType::Q);
 
-        match self.get_ids(ngrams, Some(count as usize)) {
+        match self.get_ids(ngrams, Some(count as usize), fuzzy) {
             Ok(ids) => ids,
             Err(err) => panic!("Failed to get ids with: {message}", message = err),
         }
     }
 
+    /// Looks up every query in `qvec`, exact-only. See `nget_fuzzy` to
+    /// additionally expand every ngram to the keys within
+    /// `config.fuzzy_max_edit_distance` edits of it, whether or not it has an
+    /// exact match.
     pub fn nget(&self, qvec: &Vec<String>, count: u32) -> Vec<Sid> {
+        self.nget_fuzzy(qvec, count, false)
+    }
+
+    pub fn nget_fuzzy(&self, qvec: &Vec<String>, count: u32, fuzzy: bool) -> Vec<Sid> {
         if qvec.len() == 0 || count == 0 {
             return vec![];
         }
@@ -400,7 +711,7 @@ impl Qpick {
             }
         }
 
-        match self.get_ids(ngrams, Some(count as usize)) {
+        match self.get_ids(ngrams, Some(count as usize), fuzzy) {
             Ok(ids) => ids,
             Err(err) => panic!("Failed to get ids with: {message}", message = err),
         }
@@ -443,5 +754,59 @@ impl Qpick {
     }
 }
 
+/// Blocking query API, the same behavior `Qpick::get`/`Qpick::nget` have
+/// always had. Exists as a trait so code embedding qpick can be generic over
+/// sync vs. async lookups; call `Qpick::get`/`Qpick::nget` directly when a
+/// trait isn't needed.
+pub trait SyncClient {
+    fn get(&self, query: &str, count: u32) -> Vec<Sid>;
+    fn nget(&self, qvec: &Vec<String>, count: u32) -> Vec<Sid>;
+}
+
+impl SyncClient for Qpick {
+    fn get(&self, query: &str, count: u32) -> Vec<Sid> {
+        Qpick::get(self, query, count)
+    }
+
+    fn nget(&self, qvec: &Vec<String>, count: u32) -> Vec<Sid> {
+        Qpick::nget(self, qvec, count)
+    }
+}
+
+/// Non-blocking query API for servers embedding qpick: each lookup runs the
+/// usual shard fan-out on `Qpick`'s background `async_pool` instead of the
+/// caller's thread. Implemented for `Arc<Qpick>` since the returned future
+/// must own (or share) the `Qpick` it queries past the end of this call.
+pub trait AsyncClient {
+    fn get_async(&self, query: String, count: u32) -> Box<Future<Item = Vec<Sid>, Error = Error> + Send>;
+    fn nget_async(
+        &self,
+        qvec: Vec<String>,
+        count: u32,
+    ) -> Box<Future<Item = Vec<Sid>, Error = Error> + Send>;
+}
+
+impl AsyncClient for Arc<Qpick> {
+    fn get_async(&self, query: String, count: u32) -> Box<Future<Item = Vec<Sid>, Error = Error> + Send> {
+        let qpick = Arc::clone(self);
+        Box::new(
+            self.async_pool
+                .spawn_fn(move || Ok(Qpick::get(&qpick, &query, count)) as Result<Vec<Sid>, Error>),
+        )
+    }
+
+    fn nget_async(
+        &self,
+        qvec: Vec<String>,
+        count: u32,
+    ) -> Box<Future<Item = Vec<Sid>, Error = Error> + Send> {
+        let qpick = Arc::clone(self);
+        Box::new(
+            self.async_pool
+                .spawn_fn(move || Ok(Qpick::nget(&qpick, &qvec, count)) as Result<Vec<Sid>, Error>),
+        )
+    }
+}
+
 #[allow(dead_code)]
 fn main() {}
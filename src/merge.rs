@@ -0,0 +1,45 @@
+use std::fs;
+
+use fst::map::OpBuilder;
+use fst::{Error, Map, MapBuilder, Streamer};
+
+/// Unions the per-shard FST maps produced by concurrent `shard`/`index` runs
+/// back into a single `map.N` per shard, keeping the first value seen for a
+/// key when more than one input run produced it.
+pub fn merge(path: &str, nr_shards: usize) -> Result<(), Error> {
+    for i in 0..nr_shards {
+        let pattern = format!("{}/map.{}.", path, i);
+        let mut part_paths: Vec<String> = fs::read_dir(path)
+            .map_err(Error::from)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path().to_string_lossy().into_owned())
+            .filter(|p| p.starts_with(&pattern))
+            .collect();
+        part_paths.sort();
+
+        if part_paths.is_empty() {
+            continue;
+        }
+
+        let maps: Vec<Map> = part_paths
+            .iter()
+            .map(|p| Map::from_path(p).unwrap())
+            .collect();
+
+        let mut op = OpBuilder::new();
+        for m in &maps {
+            op = op.add(m);
+        }
+
+        let out_path = format!("{}/map.{}", path, i);
+        let mut builder = MapBuilder::new(fs::File::create(&out_path)?)?;
+
+        let mut stream = op.union();
+        while let Some((key, values)) = stream.next() {
+            builder.insert(key, values[0].value)?;
+        }
+        builder.finish()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use std::collections::{HashMap, HashSet};
+
+use fst;
+
+use shard::QueryType;
+
+const NGRAM_SIZE: usize = 4;
+
+/// Splits `query` into whitespace-separated, stop-word-filtered terms and then
+/// into fixed-size char ngrams, weighting each ngram by its relevance score
+/// (looked up from the `terms_relevance` FST, defaulting to 1.0 for unknown
+/// ngrams so previously unseen terms still contribute to the query).
+pub fn parse(
+    query: &str,
+    stopwords: &HashSet<String>,
+    terms_relevance: &fst::Map,
+    _qtype: QueryType,
+) -> HashMap<String, f32> {
+    let mut ngrams: HashMap<String, f32> = HashMap::new();
+
+    for term in query.split_whitespace() {
+        let term = term.to_lowercase();
+        if stopwords.contains(&term) || term.len() < NGRAM_SIZE {
+            continue;
+        }
+
+        let chars: Vec<char> = term.chars().collect();
+        for w in chars.windows(NGRAM_SIZE) {
+            let ngram: String = w.iter().collect();
+
+            let weight = match terms_relevance.get(&ngram) {
+                Some(tr) => (tr as f32) / 100.0,
+                None => 1.0,
+            };
+
+            let entry = ngrams.entry(ngram).or_insert(0.0);
+            *entry = if *entry > weight { *entry } else { weight };
+        }
+    }
+
+    ngrams
+}
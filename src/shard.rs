@@ -0,0 +1,65 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use util;
+
+/// Which of the two ngram vocabularies a query is being matched against:
+/// `Q` indexes free-text queries, `T` indexes titles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryType {
+    Q,
+    T,
+}
+
+/// Splits the tab-separated `(id, query)` pairs in `file_path` into `nr_shards`
+/// files under `output_dir`, assigning each line to a shard via
+/// `util::jump_consistent_hash_str` on the query string so that sharding and
+/// querying agree on which shard an ngram belongs to.
+pub fn shard(
+    file_path: &str,
+    nr_shards: usize,
+    output_dir: &str,
+    concurrency: usize,
+) -> io::Result<()> {
+    let writers: Vec<Arc<Mutex<BufWriter<File>>>> = (0..nr_shards)
+        .map(|i| {
+            let f = File::create(format!("{}/shard.{}.input", output_dir, i))?;
+            Ok(Arc::new(Mutex::new(BufWriter::new(f))))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let file = File::open(file_path)?;
+    let reader = BufReader::new(file);
+    let lines: Vec<String> = reader.lines().collect::<io::Result<Vec<_>>>()?;
+
+    let chunk_size = (lines.len() / concurrency.max(1)).max(1);
+    let writers = Arc::new(writers);
+
+    let handles: Vec<_> = lines
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let chunk = chunk.to_vec();
+            let writers = Arc::clone(&writers);
+            let nr_shards = nr_shards;
+
+            thread::spawn(move || -> io::Result<()> {
+                for line in chunk {
+                    let query = line.split('\t').nth(1).unwrap_or(&line);
+                    let shard_id = util::jump_consistent_hash_str(query, nr_shards as u32) as usize;
+                    let mut w = writers[shard_id].lock().unwrap();
+                    writeln!(w, "{}", line)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for h in handles {
+        h.join().unwrap()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,522 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// One (ngram, posting) record flowing through the external sort. Carries
+/// its own ngram rather than being grouped under a shared key because each
+/// shard's ingestion already reads from its own `shard.N.input` file (see
+/// `shard::shard`), so the sort only ever has to order within a single
+/// shard.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub ngram: String,
+    pub pqid: u32,
+    pub reminder: u8,
+    pub tr: u8,
+    pub f: u8,
+}
+
+impl Record {
+    // Rough in-memory/on-disk size: a 2-byte length prefix, the ngram's
+    // bytes, and the four fixed posting fields. Used to decide when the
+    // ingestion buffer has hit its byte budget.
+    fn encoded_len(&self) -> usize {
+        2 + self.ngram.len() + 4 + 1 + 1 + 1
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u16::<LittleEndian>(self.ngram.len() as u16)?;
+        w.write_all(self.ngram.as_bytes())?;
+        w.write_u32::<LittleEndian>(self.pqid)?;
+        w.write_u8(self.reminder)?;
+        w.write_u8(self.tr)?;
+        w.write_u8(self.f)?;
+        Ok(())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Option<Record>> {
+        let ngram_len = match r.read_u16::<LittleEndian>() {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut ngram_buf = vec![0u8; ngram_len as usize];
+        r.read_exact(&mut ngram_buf)?;
+
+        Ok(Some(Record {
+            ngram: String::from_utf8(ngram_buf).unwrap(),
+            pqid: r.read_u32::<LittleEndian>()?,
+            reminder: r.read_u8()?,
+            tr: r.read_u8()?,
+            f: r.read_u8()?,
+        }))
+    }
+}
+
+/// Bounded-memory external sort: buffers `Record`s up to `byte_budget`,
+/// sorting and spilling the buffer to `spill_dir` each time it fills, so
+/// indexing a shard never holds more than `byte_budget` bytes of postings in
+/// memory regardless of how large the shard's input is.
+pub struct ExternalSorter {
+    spill_dir: String,
+    byte_budget: usize,
+    buffer: Vec<Record>,
+    buffer_bytes: usize,
+    run_paths: Vec<String>,
+}
+
+impl ExternalSorter {
+    pub fn new(spill_dir: &str, byte_budget: usize) -> io::Result<ExternalSorter> {
+        fs::create_dir_all(spill_dir)?;
+        Ok(ExternalSorter {
+            spill_dir: spill_dir.to_string(),
+            byte_budget: byte_budget,
+            buffer: Vec::new(),
+            buffer_bytes: 0,
+            run_paths: Vec::new(),
+        })
+    }
+
+    pub fn push(&mut self, record: Record) -> io::Result<()> {
+        self.buffer_bytes += record.encoded_len();
+        self.buffer.push(record);
+
+        if self.buffer_bytes >= self.byte_budget {
+            self.spill()?;
+        }
+
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        self.buffer.sort_by(|a, b| a.ngram.cmp(&b.ngram));
+
+        let run_path = format!("{}/run.{}.spill", self.spill_dir, self.run_paths.len());
+        let mut writer = BufWriter::new(File::create(&run_path)?);
+        for record in self.buffer.drain(..) {
+            record.write_to(&mut writer)?;
+        }
+        writer.flush()?;
+
+        self.run_paths.push(run_path);
+        self.buffer_bytes = 0;
+
+        Ok(())
+    }
+
+    /// Finalizes ingestion and returns every pushed record in non-decreasing
+    /// ngram order (records sharing an ngram are adjacent, their relative
+    /// order is not defined) — streamed via a k-way merge over the spilled
+    /// runs when there are any, or a plain in-memory sort when the whole
+    /// shard fit in one buffer. Callers must drain the result fully, in
+    /// order, since that's what keeps keys reaching the FST builder strictly
+    /// increasing.
+    pub fn finish(mut self) -> io::Result<MergedRecords> {
+        if self.run_paths.is_empty() {
+            self.buffer.sort_by(|a, b| a.ngram.cmp(&b.ngram));
+            return Ok(MergedRecords::InMemory(self.buffer.into_iter()));
+        }
+
+        // Keep everything on disk once we've started spilling, rather than
+        // special-casing a final in-memory tail as just another run.
+        self.spill()?;
+
+        let mut cursors = Vec::with_capacity(self.run_paths.len());
+        for path in &self.run_paths {
+            cursors.push(BufReader::new(File::open(path)?));
+        }
+
+        let mut heap = BinaryHeap::new();
+        for (run, cursor) in cursors.iter_mut().enumerate() {
+            if let Some(record) = Record::read_from(cursor)? {
+                heap.push(HeapEntry { record: record, run: run });
+            }
+        }
+
+        Ok(MergedRecords::Merging { cursors: cursors, heap: heap })
+    }
+
+    /// Removes the spilled run files; best-effort, called once the merge
+    /// stream produced by `finish` has been fully drained.
+    pub fn cleanup(spill_dir: &str) {
+        let _ = fs::remove_dir_all(spill_dir);
+    }
+}
+
+struct HeapEntry {
+    record: Record,
+    run: usize,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &HeapEntry) -> bool {
+        self.record.ngram == other.record.ngram
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &HeapEntry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    // BinaryHeap is a max-heap; reverse the comparison so the
+    // lexicographically smallest ngram pops first.
+    fn cmp(&self, other: &HeapEntry) -> Ordering {
+        other.record.ngram.cmp(&self.record.ngram)
+    }
+}
+
+pub enum MergedRecords {
+    InMemory(::std::vec::IntoIter<Record>),
+    Merging {
+        cursors: Vec<BufReader<File>>,
+        heap: BinaryHeap<HeapEntry>,
+    },
+}
+
+impl Iterator for MergedRecords {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        match *self {
+            MergedRecords::InMemory(ref mut it) => it.next(),
+            MergedRecords::Merging {
+                ref mut cursors,
+                ref mut heap,
+            } => {
+                let top = match heap.pop() {
+                    Some(top) => top,
+                    None => return None,
+                };
+
+                if let Some(next_record) = Record::read_from(&mut cursors[top.run]).unwrap() {
+                    heap.push(HeapEntry {
+                        record: next_record,
+                        run: top.run,
+                    });
+                }
+
+                Some(top.record)
+            }
+        }
+    }
+}
+
+/// One document's partial squared-term-weight contribution and ngram count,
+/// flowing through the same bounded-memory external sort as `Record` but
+/// keyed by the full `qid` instead of `ngram`. Keying by the full id (rather
+/// than the shard-local `pqid`) matters here in a way it doesn't for
+/// postings: two distinct documents can land in the same shard (sharding is
+/// keyed by query text, not id, see `shard::shard`) while sharing a `pqid`
+/// (same `qid / nr_shards`, different `qid % nr_shards`), and `pqid` alone
+/// can't tell them apart. `builder::write_norms` sums same-key contributions
+/// together as it streams past them, which is only correct when that key
+/// uniquely identifies one document; sorting by `qid` here is what lets it
+/// do that with one pass over the stream instead of a `HashMap<u64, _>`
+/// sized to the shard's document count. `sq_weight` feeds the L2 norm
+/// `Cosine` scoring divides by; `ngram_count` is the document-length term
+/// `Bm25` normalizes by, kept separate since a weighted-vector magnitude and
+/// a token count aren't the same notion of "length".
+#[derive(Debug, Clone)]
+pub struct NormRecord {
+    pub qid: u64,
+    pub sq_weight: f32,
+    pub ngram_count: u32,
+}
+
+impl NormRecord {
+    fn encoded_len(&self) -> usize {
+        8 + 4 + 4
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u64::<LittleEndian>(self.qid)?;
+        w.write_f32::<LittleEndian>(self.sq_weight)?;
+        w.write_u32::<LittleEndian>(self.ngram_count)?;
+        Ok(())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Option<NormRecord>> {
+        let qid = match r.read_u64::<LittleEndian>() {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let sq_weight = r.read_f32::<LittleEndian>()?;
+        let ngram_count = r.read_u32::<LittleEndian>()?;
+
+        Ok(Some(NormRecord {
+            qid: qid,
+            sq_weight: sq_weight,
+            ngram_count: ngram_count,
+        }))
+    }
+}
+
+/// Bounded-memory external sort for `NormRecord`s, mirroring `ExternalSorter`
+/// field-for-field but ordering by `qid` rather than `ngram`.
+pub struct NormSorter {
+    spill_dir: String,
+    byte_budget: usize,
+    buffer: Vec<NormRecord>,
+    buffer_bytes: usize,
+    run_paths: Vec<String>,
+}
+
+impl NormSorter {
+    pub fn new(spill_dir: &str, byte_budget: usize) -> io::Result<NormSorter> {
+        fs::create_dir_all(spill_dir)?;
+        Ok(NormSorter {
+            spill_dir: spill_dir.to_string(),
+            byte_budget: byte_budget,
+            buffer: Vec::new(),
+            buffer_bytes: 0,
+            run_paths: Vec::new(),
+        })
+    }
+
+    pub fn push(&mut self, record: NormRecord) -> io::Result<()> {
+        self.buffer_bytes += record.encoded_len();
+        self.buffer.push(record);
+
+        if self.buffer_bytes >= self.byte_budget {
+            self.spill()?;
+        }
+
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        self.buffer.sort_by_key(|r| r.qid);
+
+        let run_path = format!("{}/norm-run.{}.spill", self.spill_dir, self.run_paths.len());
+        let mut writer = BufWriter::new(File::create(&run_path)?);
+        for record in self.buffer.drain(..) {
+            record.write_to(&mut writer)?;
+        }
+        writer.flush()?;
+
+        self.run_paths.push(run_path);
+        self.buffer_bytes = 0;
+
+        Ok(())
+    }
+
+    /// Finalizes ingestion and returns every pushed record in non-decreasing
+    /// `qid` order, streamed via a k-way merge over the spilled runs (or a
+    /// plain in-memory sort when nothing spilled). Same-`qid` records are
+    /// adjacent but not pre-summed; the caller groups and sums them while
+    /// draining, the same way `index_shard` groups postings by ngram.
+    pub fn finish(mut self) -> io::Result<MergedNorms> {
+        if self.run_paths.is_empty() {
+            self.buffer.sort_by_key(|r| r.qid);
+            return Ok(MergedNorms::InMemory(self.buffer.into_iter()));
+        }
+
+        self.spill()?;
+
+        let mut cursors = Vec::with_capacity(self.run_paths.len());
+        for path in &self.run_paths {
+            cursors.push(BufReader::new(File::open(path)?));
+        }
+
+        let mut heap = BinaryHeap::new();
+        for (run, cursor) in cursors.iter_mut().enumerate() {
+            if let Some(record) = NormRecord::read_from(cursor)? {
+                heap.push(NormHeapEntry { record: record, run: run });
+            }
+        }
+
+        Ok(MergedNorms::Merging { cursors: cursors, heap: heap })
+    }
+
+    /// Removes the spilled run files; best-effort, called once the merge
+    /// stream produced by `finish` has been fully drained.
+    pub fn cleanup(spill_dir: &str) {
+        let _ = fs::remove_dir_all(spill_dir);
+    }
+}
+
+struct NormHeapEntry {
+    record: NormRecord,
+    run: usize,
+}
+
+impl PartialEq for NormHeapEntry {
+    fn eq(&self, other: &NormHeapEntry) -> bool {
+        self.record.qid == other.record.qid
+    }
+}
+
+impl Eq for NormHeapEntry {}
+
+impl PartialOrd for NormHeapEntry {
+    fn partial_cmp(&self, other: &NormHeapEntry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NormHeapEntry {
+    // BinaryHeap is a max-heap; reverse the comparison so the smallest qid
+    // pops first.
+    fn cmp(&self, other: &NormHeapEntry) -> Ordering {
+        other.record.qid.cmp(&self.record.qid)
+    }
+}
+
+pub enum MergedNorms {
+    InMemory(::std::vec::IntoIter<NormRecord>),
+    Merging {
+        cursors: Vec<BufReader<File>>,
+        heap: BinaryHeap<NormHeapEntry>,
+    },
+}
+
+impl Iterator for MergedNorms {
+    type Item = NormRecord;
+
+    fn next(&mut self) -> Option<NormRecord> {
+        match *self {
+            MergedNorms::InMemory(ref mut it) => it.next(),
+            MergedNorms::Merging {
+                ref mut cursors,
+                ref mut heap,
+            } => {
+                let top = match heap.pop() {
+                    Some(top) => top,
+                    None => return None,
+                };
+
+                if let Some(next_record) = NormRecord::read_from(&mut cursors[top.run]).unwrap() {
+                    heap.push(NormHeapEntry {
+                        record: next_record,
+                        run: top.run,
+                    });
+                }
+
+                Some(top.record)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spill_dir(name: &str) -> String {
+        format!(
+            "{}/qpick-test-{}-{}",
+            ::std::env::temp_dir().display(),
+            name,
+            ::std::process::id()
+        )
+    }
+
+    #[test]
+    fn test_external_sorter_merges_spilled_runs_in_order() {
+        let dir = spill_dir("sorter");
+
+        // A byte budget this tiny forces a spill after just a few pushes,
+        // so `finish` has to k-way merge multiple runs rather than taking
+        // the plain in-memory sort path.
+        let mut sorter = ExternalSorter::new(&dir, 40).unwrap();
+
+        let ngrams = ["zzzz", "aaaa", "mmmm", "bbbb", "aaaa", "cccc"];
+        for (i, &ngram) in ngrams.iter().enumerate() {
+            sorter
+                .push(Record {
+                    ngram: ngram.to_string(),
+                    pqid: i as u32,
+                    reminder: 0,
+                    tr: 100,
+                    f: 1,
+                })
+                .unwrap();
+        }
+
+        let output: Vec<Record> = sorter.finish().unwrap().collect();
+        assert_eq!(output.len(), ngrams.len());
+
+        for pair in output.windows(2) {
+            assert!(pair[0].ngram <= pair[1].ngram);
+        }
+
+        let mut sorted_ngrams = ngrams.to_vec();
+        sorted_ngrams.sort();
+        let output_ngrams: Vec<&str> = output.iter().map(|r| r.ngram.as_str()).collect();
+        assert_eq!(output_ngrams, sorted_ngrams);
+
+        ExternalSorter::cleanup(&dir);
+    }
+
+    #[test]
+    fn test_external_sorter_in_memory_path_also_sorts() {
+        let dir = spill_dir("sorter-in-memory");
+
+        // A generous budget means nothing spills; exercises the
+        // `MergedRecords::InMemory` branch of `finish`.
+        let mut sorter = ExternalSorter::new(&dir, 1024 * 1024).unwrap();
+        for ngram in &["banana", "apple", "cherry"] {
+            sorter
+                .push(Record {
+                    ngram: ngram.to_string(),
+                    pqid: 0,
+                    reminder: 0,
+                    tr: 0,
+                    f: 1,
+                })
+                .unwrap();
+        }
+
+        let output: Vec<String> = sorter.finish().unwrap().map(|r| r.ngram).collect();
+        assert_eq!(output, vec!["apple", "banana", "cherry"]);
+
+        ExternalSorter::cleanup(&dir);
+    }
+
+    #[test]
+    fn test_norm_sorter_merges_spilled_runs_by_qid() {
+        let dir = spill_dir("norm-sorter");
+
+        let mut sorter = NormSorter::new(&dir, 16).unwrap();
+        for &(qid, sq_weight) in &[(3u64, 1.0f32), (1, 2.0), (2, 3.0), (1, 4.0)] {
+            sorter
+                .push(NormRecord {
+                    qid: qid,
+                    sq_weight: sq_weight,
+                    ngram_count: 1,
+                })
+                .unwrap();
+        }
+
+        let output: Vec<NormRecord> = sorter.finish().unwrap().collect();
+        let qids: Vec<u64> = output.iter().map(|r| r.qid).collect();
+
+        for pair in qids.windows(2) {
+            assert!(pair[0] <= pair[1]);
+        }
+        assert_eq!(output.len(), 4);
+
+        NormSorter::cleanup(&dir);
+    }
+}
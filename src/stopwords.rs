@@ -0,0 +1,20 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+
+pub fn load(path: &str) -> io::Result<HashSet<String>> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+
+    let mut stopwords = HashSet::new();
+    for line in reader.lines() {
+        let word = line?;
+        let word = word.trim();
+        if !word.is_empty() {
+            stopwords.insert(word.to_string());
+        }
+    }
+
+    Ok(stopwords)
+}
@@ -0,0 +1,123 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Szudzik's "elegant pairing" function: packs two non-negative integers
+/// into a single one, used to store `(addr, len)` as a single FST value.
+pub fn elegant_pair(x: u64, y: u64) -> u64 {
+    if x >= y {
+        x * x + x + y
+    } else {
+        y * y + x
+    }
+}
+
+/// Inverse of `elegant_pair`: unpacks a value produced by it back into `(x, y)`.
+pub fn elegant_pair_inv(z: u64) -> (u64, u64) {
+    let q = (z as f64).sqrt() as u64;
+    if z - q * q >= q {
+        (q, z - q * q - q)
+    } else {
+        (z - q * q, q)
+    }
+}
+
+#[inline]
+pub fn min(a: f32, b: f32) -> f32 {
+    if a < b {
+        a
+    } else {
+        b
+    }
+}
+
+#[inline]
+pub fn as_ptr(data: &[u8]) -> *const u8 {
+    data.as_ptr()
+}
+
+/// Recovers the original (unsharded) document id from a shard-local packed id
+/// and the shard remainder it was split off with.
+#[inline]
+pub fn pqid2qid(pqid: u64, reminder: u8, nr_shards: usize) -> u64 {
+    pqid * nr_shards as u64 + reminder as u64
+}
+
+fn str_hash(s: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    s.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Jump consistent hash (Lamping & Veach), bucketing `key` into one of `nr_shards` buckets.
+pub fn jump_consistent_hash(key: u64, nr_shards: u32) -> u32 {
+    let mut key = key;
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+
+    while j < nr_shards as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+
+    b as u32
+}
+
+#[inline]
+pub fn jump_consistent_hash_str(key: &str, nr_shards: u32) -> u32 {
+    jump_consistent_hash(str_hash(key), nr_shards)
+}
+
+/// Plain Levenshtein edit distance between two strings, used to turn a
+/// Levenshtein-automaton match back into an actual distance for scoring.
+pub fn levenshtein_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for i in 1..=a.len() {
+        let mut prev = row[0];
+        row[0] = i;
+
+        for j in 1..=b.len() {
+            let cur = row[j];
+            row[j] = if a[i - 1] == b[j - 1] {
+                prev
+            } else {
+                1 + std::cmp::min(prev, std::cmp::min(row[j], row[j - 1]))
+            };
+            prev = cur;
+        }
+    }
+
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elegant_pair_roundtrip() {
+        for &(x, y) in &[(0, 0), (1, 0), (0, 1), (42, 7), (1000, 999999)] {
+            let z = elegant_pair(x, y);
+            assert_eq!(elegant_pair_inv(z), (x, y));
+        }
+    }
+
+    #[test]
+    fn test_jump_consistent_hash_stable() {
+        let h1 = jump_consistent_hash_str("abcd", 128);
+        let h2 = jump_consistent_hash_str("abcd", 128);
+        assert_eq!(h1, h2);
+        assert!(h1 < 128);
+    }
+
+    #[test]
+    fn test_levenshtein_distance() {
+        assert_eq!(levenshtein_distance("kitten", "sitting"), 3);
+        assert_eq!(levenshtein_distance("abcd", "abcd"), 0);
+        assert_eq!(levenshtein_distance("abcd", "abce"), 1);
+    }
+}